@@ -1,4 +1,6 @@
+mod price_controller;
 mod skiplist;
+pub use price_controller::PriceController;
 use skiplist::SkipList;
 use std::{cmp::Ord, collections::HashMap};
 
@@ -17,6 +19,20 @@ type Map<K, V> = HashMap<K, V>;
 type Block = i128;
 type Account = [u8; 20];
 
+// Upper bound on how many skiplist entries a single `collect()` call will
+// process, so a long-neglected backlog of price-change blocks can't make
+// one call unboundedly expensive. Callers simply call `collect()` again to
+// resume where the last call left off.
+const MAX_CHANGES_PER_CALL: usize = 64;
+
+// Credit parameters for the per-account rate limiter below, modeled on
+// OpenEthereum's LES request-credit buffer: an account can burst up to
+// `CREDIT_CAP` worth of skiplist-mutating calls, then has to wait for
+// credits to recharge at `CREDIT_RECHARGE_PER_BLOCK` before mutating again.
+const CREDIT_CAP: i128 = 100;
+const CREDIT_RECHARGE_PER_BLOCK: i128 = 1;
+const MUTATION_CREDIT_COST: i128 = 25;
+
 // TODO: Consts
 // By doing 1 epoch per hour there would be ~> 265 blocks per epoch.
 // Then if the skiplist has 16 levels, the fastest level would skip
@@ -60,12 +76,94 @@ impl Collector {
     }
 }
 
-pub struct SubscriptionManager {
+// Bounds how often one account can insert or move a node in a service's
+// `changes` skiplist, so an attacker can't churn subscribe/unsubscribe to
+// exhaust the node index range or leave only low-skip nodes behind. Starts
+// full and recharges lazily, rather than on every block, since most
+// accounts will never touch it again after a recharge.
+struct CreditBalance {
+    balance: i128,
+    last_update: Block,
+}
+
+impl CreditBalance {
+    fn new(current_block: Block) -> Self {
+        Self {
+            balance: CREDIT_CAP,
+            last_update: current_block,
+        }
+    }
+
+    // Recharges for the blocks elapsed since the last touch, then debits
+    // `cost`, reverting if that would leave the balance negative.
+    fn debit(&mut self, current_block: Block, cost: i128) {
+        let elapsed = current_block - self.last_update;
+        self.balance = (self.balance + CREDIT_RECHARGE_PER_BLOCK * elapsed).min(CREDIT_CAP);
+        self.last_update = current_block;
+
+        assert!(self.balance >= cost, "insufficient credit balance for this operation");
+        self.balance -= cost;
+    }
+}
+
+// Everything a single service needs to price and collect for its own
+// subscribers, independent of every other service sharing the manager.
+struct ServiceState {
     collector: Collector,
     price_per_block: i128,
-    current_block: Block,
+    // Set once the service has called `set_price_per_block` at least once.
+    // A service is lazily created the first time anyone tops it off or
+    // schedules a price for it, so `price_per_block` alone can't
+    // distinguish "never configured" from "explicitly priced at 0".
+    price_configured: bool,
+    // A price change that the service has scheduled for a future block.
+    // Applied lazily the next time the current block is observed to have
+    // reached `effective_block`, so subscriptions opened beforehand keep
+    // the price that was live when they were opened.
+    pending_price_change: Option<(Block, i128)>,
     changes: SkipList,
-    subscriptions: Map<Account, Vec<Subscription>>,
+    // Optional automatic price tuning; services that want a fixed price
+    // simply never set one.
+    price_controller: Option<PriceController>,
+}
+
+impl ServiceState {
+    fn new() -> Self {
+        Self {
+            collector: Collector::new(),
+            price_per_block: 0,
+            price_configured: false,
+            pending_price_change: None,
+            changes: SkipList::new(),
+            price_controller: None,
+        }
+    }
+
+    // Applies a scheduled price change once its effective block has been
+    // reached. Called by the top-off paths and by collect before they read
+    // the live price, so the price is up to date by the time it's used.
+    fn apply_pending_price_change(&mut self, current_block: Block) {
+        if let Some((effective_block, price)) = self.pending_price_change {
+            if effective_block <= current_block {
+                self.price_per_block = price;
+                self.pending_price_change = None;
+            }
+        }
+    }
+}
+
+pub struct SubscriptionManager {
+    current_block: Block,
+    services: Map<Account, ServiceState>,
+    // Insertion order of services, so `collect_all` can walk them
+    // round-robin instead of relying on (unstable) HashMap iteration order.
+    service_order: Vec<Account>,
+    collect_all_cursor: usize,
+    subscriptions: Map<(Account, Account), Vec<Subscription>>,
+    // Per-account rate limit on skiplist-mutating calls, shared across
+    // every service (an account only gets one credit balance, not one per
+    // service it subscribes to).
+    credits: Map<Account, CreditBalance>,
     // TODO: Consider having a minimum time to subscribe to prevent
     // subscribing for 1 block so that the next part of the transaction
     // shows as active. Consider also having the first block be the next
@@ -77,11 +175,12 @@ pub struct SubscriptionManager {
 impl SubscriptionManager {
     pub fn new() -> Self {
         Self {
-            collector: Collector::new(),
-            price_per_block: 0,
             current_block: 0,
-            changes: SkipList::new(),
+            services: Map::new(),
+            service_order: Vec::new(),
+            collect_all_cursor: 0,
             subscriptions: Map::new(),
+            credits: Map::new(),
         }
     }
 
@@ -95,25 +194,83 @@ impl SubscriptionManager {
         self.current_block
     }
 
-    // Callable only by the service
-    pub fn set_price_per_block(&mut self, price: i128) {
-        self.price_per_block = price;
+    fn get_or_create_service(&mut self, service: Account) -> &mut ServiceState {
+        let service_order = &mut self.service_order;
+        self.services.entry(service).or_insert_with(|| {
+            service_order.push(service);
+            ServiceState::new()
+        })
+    }
+
+    fn get_or_create_credits(&mut self, account: Account) -> &mut CreditBalance {
+        let current_block = self.current_block;
+        self.credits.entry(account).or_insert_with(|| CreditBalance::new(current_block))
+    }
+
+    pub fn set_price_controller(&mut self, service: Account, controller: PriceController) {
+        self.get_or_create_service(service).price_controller = Some(controller);
+    }
+
+    pub fn clear_price_controller(&mut self, service: Account) {
+        self.get_or_create_service(service).price_controller = None;
+    }
+
+    // Callable only by the service. Rather than taking effect immediately,
+    // the price is scheduled to take effect at `effective_block`, so that
+    // subscriptions opened before the change keep their original
+    // `price_per_block` instead of being re-priced out from under them.
+    pub fn set_price_per_block(&mut self, service: Account, price: i128, effective_block: Block) {
+        assert!(effective_block > self.current_block);
+        let state = self.get_or_create_service(service);
+        state.pending_price_change = Some((effective_block, price));
+        state.price_configured = true;
+    }
+
+    pub fn top_off(&mut self, service: Account, account: Account, amount: i128) {
+        let current_block = self.current_block;
+        let state = self.get_or_create_service(service);
+        state.apply_pending_price_change(current_block);
+        assert!(state.price_configured, "service has no price configured");
+        let price_per_block = state.price_per_block;
+        self.top_off_at_price(service, account, amount, price_per_block);
     }
 
-    pub fn top_off(&mut self, account: Account, amount: i128) {
-        let price_per_block = self.price_per_block;
-        let current_block = self.current_block();
+    // Pays the live price, but reverts if it has risen above
+    // `max_price_per_block`, so a consumer's transaction
+    // can't be re-priced out from under them by a price change landing in
+    // the same block.
+    pub fn top_off_with_max_price(
+        &mut self,
+        service: Account,
+        account: Account,
+        amount: i128,
+        max_price_per_block: i128,
+    ) {
+        let current_block = self.current_block;
+        let state = self.get_or_create_service(service);
+        state.apply_pending_price_change(current_block);
+        assert!(state.price_configured, "service has no price configured");
+        assert!(
+            state.price_per_block <= max_price_per_block,
+            "price_per_block exceeds max_price_per_block"
+        );
+        let price_per_block = state.price_per_block;
+        self.top_off_at_price(service, account, amount, price_per_block);
+    }
+
+    fn top_off_at_price(&mut self, service: Account, account: Account, amount: i128, price_per_block: i128) {
+        let current_block = self.current_block;
         let num_blocks = amount / price_per_block;
         if num_blocks == 0 {
-            // TODO: Actually we want to allow the consumer specify the price
-            // so they are resiliant to recent changes. Or, we could schedule
-            // changes in price for the future.
             return;
         }
 
-        self.collector.balance += amount;
+        self.get_or_create_credits(account).debit(current_block, MUTATION_CREDIT_COST);
 
-        let subs = self.subscriptions.entry(account).or_default();
+        let state = self.services.get_mut(&service).expect("service was just created");
+        state.collector.balance += amount;
+
+        let subs = self.subscriptions.entry((service, account)).or_default();
 
         let start_block = subs
             .last()
@@ -128,12 +285,12 @@ impl SubscriptionManager {
             price_per_block,
         });
 
-        *self.changes.get_or_insert_mut(start_block) += price_per_block as i128;
-        *self.changes.get_or_insert_mut(end_block) -= price_per_block as i128;
+        *state.changes.get_or_insert_mut(start_block) += price_per_block as i128;
+        *state.changes.get_or_insert_mut(end_block) -= price_per_block as i128;
     }
 
-    pub fn is_active(&self, account: Account) -> bool {
-        let subs = if let Some(subs) = self.subscriptions.get(&account) {
+    pub fn is_active(&self, service: Account, account: Account) -> bool {
+        let subs = if let Some(subs) = self.subscriptions.get(&(service, account)) {
             subs
         } else {
             return false;
@@ -150,22 +307,152 @@ impl SubscriptionManager {
         return false;
     }
 
-    pub fn collect(&mut self) {
-        // Process all changes
+    // Cancels an account's subscription(s) to a service and refunds the
+    // unused tail out of the service's collector balance. Blocks at or
+    // before the current block are never refunded, since they're already
+    // owed to the service; unwinds from the latest subscription backward to
+    // handle an account that has stacked several (e.g. from topping off
+    // again after a price change).
+    pub fn unsubscribe(&mut self, service: Account, account: Account) {
+        let cutoff = self.current_block() + 1;
+
+        // Peek whether there's actually anything to unwind before touching
+        // the credit balance, so a no-op unsubscribe doesn't cost anything.
+        let will_refund = self
+            .subscriptions
+            .get(&(service, account))
+            .and_then(|subs| subs.last())
+            .map(|sub| sub.end_block > cutoff)
+            .unwrap_or(false);
+        if !will_refund {
+            return;
+        }
+
+        let current_block = self.current_block;
+        self.get_or_create_credits(account).debit(current_block, MUTATION_CREDIT_COST);
+
+        let subs = match self.subscriptions.get_mut(&(service, account)) {
+            Some(subs) => subs,
+            None => return,
+        };
+        let state = match self.services.get_mut(&service) {
+            Some(state) => state,
+            None => return,
+        };
+
+        while let Some(sub) = subs.last_mut() {
+            if sub.end_block <= cutoff {
+                // Already fully elapsed (or not refundable); nothing left
+                // to unwind below this one either, since subscriptions are
+                // stacked in non-decreasing block order.
+                break;
+            }
+
+            let new_end_block = cutoff.max(sub.start_block);
+            let unused_blocks = sub.end_block - new_end_block;
+            let refund = unused_blocks * sub.price_per_block;
+            state.collector.balance -= refund;
+
+            // The original `-price_per_block` delta at the old expiry
+            // moves to the new, earlier one.
+            *state.changes.get_or_insert_mut(sub.end_block) += sub.price_per_block;
+            *state.changes.get_or_insert_mut(new_end_block) -= sub.price_per_block;
+
+            sub.end_block = new_end_block;
+
+            if sub.end_block <= sub.start_block {
+                // Entirely unwound (it hadn't started yet); drop it and
+                // keep unwinding any earlier stacked subscriptions.
+                subs.pop();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn collect(&mut self, service: Account) {
+        self.collect_bounded(service, MAX_CHANGES_PER_CALL);
+    }
+
+    // Walks every known service round-robin, with all of them sharing the
+    // same bounded-work cap as a single `collect()` call, so one neglected
+    // service can't starve the rest. Each call starts one service further
+    // along than the previous call did.
+    pub fn collect_all(&mut self) {
+        let num_services = self.service_order.len();
+        if num_services == 0 {
+            return;
+        }
+
+        let mut budget = MAX_CHANGES_PER_CALL;
+        for i in 0..num_services {
+            if budget == 0 {
+                break;
+            }
+            let service = self.service_order[(self.collect_all_cursor + i) % num_services];
+            budget -= self.collect_bounded(service, budget);
+        }
+
+        self.collect_all_cursor = (self.collect_all_cursor + 1) % num_services;
+    }
+
+    // Processes at most `budget` pending skiplist entries for `service`,
+    // returning how many were actually processed.
+    fn collect_bounded(&mut self, service: Account, budget: usize) -> usize {
+        let current_block = self.current_block;
+        let state = match self.services.get_mut(&service) {
+            Some(state) => state,
+            None => return 0,
+        };
+
+        // Otherwise a schedule that lands with no top-off afterward leaves
+        // `price_per_block` stale indefinitely, and the price controller
+        // below would retune off that stale baseline.
+        state.apply_pending_price_change(current_block);
+
+        let block_before = state.collector.last_collected_block;
+        let balance_before = state.collector.service_balance;
+
+        // Process changes up to the current block, but no more than
+        // `budget` of them, so a large backlog is drained across several
+        // calls rather than in one unbounded pass.
         let mut changes_processed = 0;
-        // TODO: For security we need to cap the changes
-        // processed to some const
-        for (&block, &delta) in self.changes.iter() {
-            if block > self.current_block() {
+        let mut capped = false;
+        for (&block, &delta) in state.changes.iter() {
+            if block > current_block {
                 break;
             }
-            self.collector.collect_one(block);
-            self.collector.current_fee += delta;
+            if changes_processed >= budget {
+                capped = true;
+                break;
+            }
+            state.collector.collect_one(block);
+            state.collector.current_fee += delta;
             changes_processed += 1;
         }
 
-        self.changes.truncate_front(changes_processed);
-        self.collector.collect_one(self.current_block());
+        state.changes.truncate_front(changes_processed);
+
+        // If we hit the cap, `last_collected_block` stays at the last
+        // change actually processed; a later collect() resumes from there
+        // instead of skipping ahead to `current_block`.
+        if !capped {
+            state.collector.collect_one(current_block);
+        }
+
+        // Use how far `last_collected_block` actually advanced, not
+        // `current_block`, so a capped call doesn't tell the controller
+        // the revenue was earned over a span much larger than what was
+        // actually collected.
+        let blocks = state.collector.last_collected_block - block_before;
+        let revenue = state.collector.service_balance - balance_before;
+        if let Some(controller) = &mut state.price_controller {
+            if let Some(new_price) = controller.retune(current_block, state.price_per_block, revenue, blocks) {
+                state.pending_price_change = Some((current_block + 1, new_price));
+            }
+        }
+
+        changes_processed
     }
 }
 
@@ -177,70 +464,85 @@ mod tests {
         [id; 20]
     }
 
+    fn service(id: u8) -> Account {
+        [100 + id; 20]
+    }
+
+    // Schedules `price` to take effect immediately for a freshly created
+    // manager (current block 0), mirroring the old single-service tests'
+    // direct field assignment.
+    fn set_initial_price(subs: &mut SubscriptionManager, service: Account, price: i128) {
+        subs.set_price_per_block(service, price, 1);
+    }
+
     #[test]
     pub fn one_complete_subscription() {
         let mut subs = SubscriptionManager::new();
-        subs.price_per_block = 10;
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
 
         // Buy blocks 6-16
         subs.set_current_block(5);
-        subs.top_off(account(1), 100);
+        subs.top_off(svc, account(1), 100);
 
         subs.set_current_block(100);
-        subs.collect();
+        subs.collect(svc);
 
-        assert_eq!(subs.collector.service_balance, 100);
+        assert_eq!(subs.services[&svc].collector.service_balance, 100);
     }
 
     #[test]
     pub fn subscription_is_active_for_required_blocks() {
         let mut subs = SubscriptionManager::new();
-        subs.price_per_block = 10;
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
 
         let account = account(1);
 
         // Buy blocks 6-16
         subs.set_current_block(5);
-        subs.top_off(account, 100);
+        subs.top_off(svc, account, 100);
 
-        assert_eq!(false, subs.is_active(account));
+        assert_eq!(false, subs.is_active(svc, account));
         for i in 6..16 {
             subs.set_current_block(i);
-            assert_eq!(true, subs.is_active(account));
+            assert_eq!(true, subs.is_active(svc, account));
         }
         subs.set_current_block(16);
-        assert_eq!(false, subs.is_active(account));
+        assert_eq!(false, subs.is_active(svc, account));
     }
 
     #[test]
     pub fn loop_collect() {
         let mut subs = SubscriptionManager::new();
-        subs.price_per_block = 5;
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 5);
 
         // Buy blocks 6-16
         subs.set_current_block(1);
-        subs.top_off(account(1), 100);
+        subs.top_off(svc, account(1), 100);
 
         for i in 2..30 {
             subs.set_current_block(i);
-            subs.collect();
+            subs.collect(svc);
         }
 
-        assert_eq!(subs.collector.service_balance, 100);
+        assert_eq!(subs.services[&svc].collector.service_balance, 100);
     }
 
     #[test]
     pub fn overlapping_subscriptions() {
         let mut subs = SubscriptionManager::new();
-        subs.price_per_block = 10;
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
 
         // Buy blocks 6-15 (inclusive)
         subs.set_current_block(5);
-        subs.top_off(account(1), 100);
+        subs.top_off(svc, account(1), 100);
 
         // Buy blocks 11-31 (inclusive)
         subs.set_current_block(10);
-        subs.top_off(account(2), 200);
+        subs.top_off(svc, account(2), 200);
 
         // Collect:
         //   6-10  (5 blocks) @ 1 +
@@ -248,8 +550,363 @@ mod tests {
         //   16-18 (3 blocks) @ 1
         // = 19 subscribed blocks = * 10 = 190
         subs.set_current_block(19);
-        subs.collect();
+        subs.collect(svc);
+
+        assert_eq!(subs.services[&svc].collector.service_balance, 180);
+    }
+
+    #[test]
+    pub fn services_collect_independently() {
+        let mut subs = SubscriptionManager::new();
+        let svc_a = service(1);
+        let svc_b = service(2);
+        set_initial_price(&mut subs, svc_a, 10);
+        set_initial_price(&mut subs, svc_b, 7);
+
+        subs.set_current_block(5);
+        subs.top_off(svc_a, account(1), 100);
+        subs.top_off(svc_b, account(1), 70);
+
+        subs.set_current_block(100);
+        subs.collect(svc_a);
+
+        // svc_b hasn't been collected yet, even though the account is
+        // active on both.
+        assert_eq!(subs.services[&svc_a].collector.service_balance, 100);
+        assert_eq!(subs.services[&svc_b].collector.service_balance, 0);
+
+        subs.collect(svc_b);
+        assert_eq!(subs.services[&svc_b].collector.service_balance, 70);
+    }
+
+    #[test]
+    pub fn collect_all_walks_every_service_round_robin() {
+        let mut subs = SubscriptionManager::new();
+        let svc_a = service(1);
+        let svc_b = service(2);
+        set_initial_price(&mut subs, svc_a, 10);
+        set_initial_price(&mut subs, svc_b, 7);
+
+        subs.set_current_block(5);
+        subs.top_off(svc_a, account(1), 100);
+        subs.top_off(svc_b, account(1), 70);
+
+        subs.set_current_block(100);
+        subs.collect_all();
+
+        assert_eq!(subs.services[&svc_a].collector.service_balance, 100);
+        assert_eq!(subs.services[&svc_b].collector.service_balance, 70);
+    }
+
+    #[test]
+    pub fn top_off_with_max_price_locks_in_quoted_price() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        subs.set_current_block(5);
+        // The price rises before the consumer's transaction lands, but
+        // they quoted a cap high enough to still clear.
+        subs.set_price_per_block(svc, 20, 6);
+        subs.set_current_block(6);
+        subs.top_off_with_max_price(svc, account(1), 100, 20);
+
+        subs.set_current_block(7);
+        assert_eq!(true, subs.is_active(svc, account(1)));
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn top_off_with_max_price_reverts_if_price_exceeds_cap() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        subs.set_current_block(5);
+        subs.set_price_per_block(svc, 20, 6);
+        subs.set_current_block(6);
+        subs.top_off_with_max_price(svc, account(1), 100, 15);
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn top_off_reverts_if_the_service_was_never_given_a_price() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+
+        // No set_price_per_block call for this service: top_off should
+        // revert with a clear message instead of dividing by a
+        // price_per_block that's still at its default of 0.
+        subs.top_off(svc, account(1), 100);
+    }
+
+    #[test]
+    pub fn scheduled_price_change_does_not_affect_open_subscriptions() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        subs.set_current_block(5);
+        subs.top_off(svc, account(1), 100);
+
+        // Schedule a price increase for a future block; it should not
+        // retroactively affect the subscription opened above.
+        subs.set_price_per_block(svc, 20, 50);
+        subs.set_current_block(49);
+        subs.top_off(svc, account(2), 100);
+        assert_eq!(10, subs.services[&svc].price_per_block);
+
+        subs.set_current_block(50);
+        subs.top_off(svc, account(3), 100);
+        assert_eq!(20, subs.services[&svc].price_per_block);
+        assert_eq!(6, subs.subscriptions[&(svc, account(3))][0].end_block - 50);
+        assert_eq!(10, subs.subscriptions[&(svc, account(1))][0].price_per_block);
+    }
+
+    #[test]
+    pub fn collect_applies_a_scheduled_price_change_with_no_top_off_afterward() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        subs.set_current_block(5);
+        subs.set_price_per_block(svc, 1000, 6);
+
+        // No top_off after the effective block: only collect() observes
+        // the new current block, so it must be the one to apply the
+        // pending price change rather than leaving it stale forever.
+        subs.set_current_block(7);
+        subs.collect(svc);
+
+        assert_eq!(1000, subs.services[&svc].price_per_block);
+    }
+
+    #[test]
+    pub fn collect_drains_a_large_backlog_across_multiple_calls() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        // Buy enough overlapping subscriptions that the skiplist ends up
+        // with more entries than a single collect() call is allowed to
+        // process.
+        const NUM_SUBSCRIPTIONS: u16 = 2 * MAX_CHANGES_PER_CALL as u16;
+        for i in 0..NUM_SUBSCRIPTIONS {
+            subs.set_current_block(i as Block + 1);
+            subs.top_off(svc, account((i % 256) as u8), 100);
+        }
+
+        let mut calls = 0;
+        subs.set_current_block(NUM_SUBSCRIPTIONS as Block + 20);
+        loop {
+            subs.collect(svc);
+            calls += 1;
+            if subs.services[&svc].collector.last_collected_block >= subs.current_block() {
+                break;
+            }
+            assert!(calls < 1000, "collect() never caught up to current_block");
+        }
+
+        // More than one call was required to drain the backlog...
+        assert!(calls > 1);
+        // ...but the final result is the same as if the cap didn't exist:
+        // every block of every subscription was paid for in full.
+        assert_eq!(
+            subs.services[&svc].collector.service_balance,
+            NUM_SUBSCRIPTIONS as i128 * 100
+        );
+    }
+
+    #[test]
+    pub fn capped_collect_feeds_the_price_controller_only_the_blocks_it_actually_processed() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        // A wide but not unlimited band: a correctly computed margin over
+        // the ~64 blocks a single capped call can process should sit
+        // comfortably inside it.
+        subs.set_price_controller(svc, PriceController::new(10, -50, 0, 50, 0, 0));
+
+        // Build a backlog deep enough that one collect() call can't drain
+        // it, directly on the service state so this doesn't depend on (or
+        // get throttled by) top_off's own bookkeeping.
+        {
+            let state = subs.services.get_mut(&svc).unwrap();
+            state.price_per_block = 10;
+            state.price_configured = true;
+            *state.changes.get_or_insert_mut(1) += 10;
+            for block in 2..(2 * MAX_CHANGES_PER_CALL as Block + 50) {
+                *state.changes.get_or_insert_mut(block) += 0;
+            }
+        }
+
+        // The current block is far beyond what this single call will
+        // reach; if `blocks` were computed against it instead of against
+        // how far `last_collected_block` actually advanced, the implied
+        // per-block revenue would collapse toward zero and falsely breach
+        // the band.
+        subs.set_current_block(2 * MAX_CHANGES_PER_CALL as Block + 200);
+        subs.collect(svc);
+
+        assert_eq!(subs.services[&svc].price_per_block, 10);
+        assert!(subs.services[&svc].pending_price_change.is_none());
+    }
+
+    #[test]
+    pub fn unsubscribe_refunds_unused_tail() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        // Buy blocks 6-15 (10 blocks).
+        subs.set_current_block(5);
+        subs.top_off(svc, account(1), 100);
+
+        // Unsubscribe partway through, after 4 blocks have elapsed.
+        subs.set_current_block(9);
+        subs.unsubscribe(svc, account(1));
+
+        // Blocks 6-9 are already owed; 10-15 (6 blocks) are refunded.
+        assert_eq!(subs.services[&svc].collector.balance, 100 - 60);
+
+        subs.set_current_block(100);
+        subs.collect(svc);
+        assert_eq!(subs.services[&svc].collector.service_balance, 40);
+    }
+
+    #[test]
+    pub fn unsubscribe_is_inactive_starting_the_next_block() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        subs.set_current_block(5);
+        subs.top_off(svc, account(1), 100);
+
+        subs.set_current_block(9);
+        subs.unsubscribe(svc, account(1));
+
+        assert_eq!(true, subs.is_active(svc, account(1)));
+        subs.set_current_block(10);
+        assert_eq!(false, subs.is_active(svc, account(1)));
+    }
+
+    #[test]
+    pub fn unsubscribe_unwinds_a_fully_future_subscription() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        subs.set_current_block(5);
+        // Never started yet relative to the unsubscribe below.
+        subs.top_off(svc, account(1), 100);
+
+        subs.unsubscribe(svc, account(1));
+
+        assert_eq!(subs.services[&svc].collector.balance, 0);
+        assert_eq!(false, subs.is_active(svc, account(1)));
+
+        subs.set_current_block(100);
+        subs.collect(svc);
+        assert_eq!(subs.services[&svc].collector.service_balance, 0);
+    }
+
+    #[test]
+    pub fn unsubscribe_unwinds_stacked_subscriptions_from_the_latest() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+
+        let a = account(1);
+
+        // Buy blocks 6-15, then top off again before using any of it,
+        // stacking a second subscription for blocks 16-25.
+        subs.set_current_block(5);
+        subs.top_off(svc, a, 100);
+        subs.top_off(svc, a, 100);
+
+        subs.set_current_block(9);
+        subs.unsubscribe(svc, a);
+
+        // Only blocks 6-9 of the first subscription are owed; the rest of
+        // the first and all of the stacked second subscription refund.
+        assert_eq!(subs.services[&svc].collector.balance, 100 + 100 - 60 - 100);
+
+        subs.set_current_block(100);
+        subs.collect(svc);
+        assert_eq!(subs.services[&svc].collector.service_balance, 40);
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn rapid_top_offs_from_one_account_are_throttled() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+        let a = account(1);
+
+        subs.set_current_block(5);
+        // The first four mutations exactly exhaust the account's credit cap.
+        for _ in 0..4 {
+            subs.top_off(svc, a, 100);
+        }
+        // A fifth in the same block has nothing left to spend.
+        subs.top_off(svc, a, 100);
+    }
+
+    #[test]
+    pub fn credit_throttling_is_per_account() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+        let a = account(1);
+        let b = account(2);
+
+        subs.set_current_block(5);
+        for _ in 0..4 {
+            subs.top_off(svc, a, 100);
+        }
+
+        // `a` is out of credit for this block, but `b` has never touched
+        // the skiplist and is unaffected.
+        subs.top_off(svc, b, 100);
+        assert_eq!(subs.services[&svc].collector.balance, 500);
+    }
+
+    #[test]
+    pub fn credits_recharge_over_time() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+        let a = account(1);
+
+        subs.set_current_block(5);
+        for _ in 0..4 {
+            subs.top_off(svc, a, 100);
+        }
+
+        // Enough blocks pass for the account's credit to recharge the cost
+        // of another mutation.
+        subs.set_current_block(5 + MUTATION_CREDIT_COST as Block);
+        subs.top_off(svc, a, 100);
+
+        assert_eq!(subs.services[&svc].collector.balance, 500);
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn rapid_unsubscribes_from_one_account_are_throttled() {
+        let mut subs = SubscriptionManager::new();
+        let svc = service(1);
+        set_initial_price(&mut subs, svc, 10);
+        let a = account(1);
+
+        subs.set_current_block(5);
+        for _ in 0..4 {
+            subs.top_off(svc, a, 100);
+        }
 
-        assert_eq!(subs.collector.service_balance, 180);
+        // The account has no credit left in this block to also pay for an
+        // unsubscribe.
+        subs.unsubscribe(svc, a);
     }
 }
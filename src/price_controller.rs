@@ -0,0 +1,133 @@
+use crate::Block;
+
+// Mirrors the Pyth Fortuna keeper, which retunes an on-chain fee to earn a
+// target percentage over the max callback cost: here we retune
+// `price_per_block` to keep realized margin over `cost_estimate` within a
+// configured band, rather than leaving the price fixed or requiring a human
+// to notice drift.
+pub struct PriceController {
+    cost_estimate: i128,
+    min_profit_pct: i128,
+    target_profit_pct: i128,
+    max_profit_pct: i128,
+    // Additional slack beyond the band before an adjustment is triggered,
+    // so the price doesn't flip back and forth right at the boundary.
+    hysteresis_pct: i128,
+    min_blocks_between_adjustments: Block,
+    last_adjustment_block: Option<Block>,
+}
+
+impl PriceController {
+    pub fn new(
+        cost_estimate: i128,
+        min_profit_pct: i128,
+        target_profit_pct: i128,
+        max_profit_pct: i128,
+        hysteresis_pct: i128,
+        min_blocks_between_adjustments: Block,
+    ) -> Self {
+        assert!(min_profit_pct <= target_profit_pct);
+        assert!(target_profit_pct <= max_profit_pct);
+        Self {
+            cost_estimate,
+            min_profit_pct,
+            target_profit_pct,
+            max_profit_pct,
+            hysteresis_pct,
+            min_blocks_between_adjustments,
+            last_adjustment_block: None,
+        }
+    }
+
+    pub fn set_cost_estimate(&mut self, cost_estimate: i128) {
+        self.cost_estimate = cost_estimate;
+    }
+
+    // Given the revenue realized and the price currently in effect over a
+    // span of blocks, returns the new price to schedule, if any adjustment
+    // is warranted. `current_block` is used only to enforce the
+    // minimum-blocks-between-adjustments guard.
+    pub fn retune(
+        &mut self,
+        current_block: Block,
+        price_per_block: i128,
+        revenue: i128,
+        blocks: Block,
+    ) -> Option<i128> {
+        if blocks <= 0 || self.cost_estimate <= 0 {
+            return None;
+        }
+        if let Some(last_adjustment_block) = self.last_adjustment_block {
+            if current_block - last_adjustment_block < self.min_blocks_between_adjustments {
+                return None;
+            }
+        }
+
+        let cost = self.cost_estimate * blocks;
+        let margin_pct = ((revenue - cost) * 100) / cost;
+
+        let breached = margin_pct < self.min_profit_pct - self.hysteresis_pct
+            || margin_pct > self.max_profit_pct + self.hysteresis_pct;
+        if !breached {
+            return None;
+        }
+
+        let revenue_per_block = revenue / blocks;
+        if revenue_per_block <= 0 {
+            return None;
+        }
+        let target_revenue_per_block = self.cost_estimate * (100 + self.target_profit_pct) / 100;
+        // Integer truncation could otherwise round this down to 0, which
+        // would leave the service unable to charge anything at all.
+        let new_price = (price_per_block * target_revenue_per_block / revenue_per_block).max(1);
+
+        self.last_adjustment_block = Some(current_block);
+        Some(new_price)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raises_price_when_margin_too_low() {
+        let mut controller = PriceController::new(8, 10, 20, 30, 2, 5);
+
+        // Revenue barely covers cost: ~0% margin, below the 10% floor.
+        let new_price = controller.retune(10, 10, 800, 100);
+
+        assert_eq!(Some(11), new_price);
+    }
+
+    #[test]
+    fn leaves_price_alone_within_band() {
+        let mut controller = PriceController::new(8, 10, 20, 30, 2, 5);
+
+        // Revenue gives exactly the target 20% margin.
+        let new_price = controller.retune(10, 10, 960, 100);
+
+        assert_eq!(None, new_price);
+    }
+
+    #[test]
+    fn respects_min_blocks_between_adjustments() {
+        let mut controller = PriceController::new(8, 10, 20, 30, 2, 50);
+
+        assert_eq!(Some(11), controller.retune(10, 10, 800, 100));
+        // Another breach arrives almost immediately, but the guard blocks
+        // a second adjustment so close to the last one.
+        assert_eq!(None, controller.retune(20, 12, 800, 100));
+    }
+
+    #[test]
+    fn never_retunes_down_to_a_zero_price() {
+        let mut controller = PriceController::new(1, 0, 0, 1, 0, 1);
+
+        // Integer truncation alone would round this down to 0, which would
+        // leave the service unable to charge anything at all.
+        let new_price = controller.retune(10, 1, 1000, 1);
+
+        assert_eq!(Some(1), new_price);
+    }
+}
@@ -1,98 +1,422 @@
 use crate::Block;
-use std::mem::transmute;
+use std::ops::{Deref, DerefMut};
 
-// TODO: An efficient version probably has something like:
-// Each level packs 4 64 bit indexes per word
-// Except the last level which has a 128bit fee delta.
-// This way, only 1 in 4 would require 2 or more words allocated.
-// Read is cheap, and modify costs 1/4 write.
+// An arena-backed, multi-level probabilistic skiplist. Each block has at
+// most one entry, and the value is the increase/decrease in price per
+// block that takes effect there.
 //
-// Each leaf node needs to store:
-//   u128 delta
-//   u64 block
-// So the below is incorrect.
-// There is some worry about the 4 billion node limit to use u32 for indices.
-// But, consider that 4 billion blocks = 2042 years.
-// If there are epochs, it can be much longer. They can upgrade the contract by then.
-// Note that "an attacker" can still exhaust the range before that time by repeatedly
-// executing subscribe/unsubscribe (at their own multi-billion dollar expense!)
-// but it is possible to have a freelist
+// There is some worry about the 4 billion node limit to use u32 for
+// indices. But, consider that 4 billion blocks = 2042 years.
+// If there are epochs, it can be much longer. They can upgrade the contract
+// by then. Note that "an attacker" can still exhaust the range before that
+// time by repeatedly executing subscribe/unsubscribe (at their own
+// multi-billion dollar expense!) but `truncate_front` recycles freed slots
+// through a freelist so ordinary churn doesn't burn through the range.
 //
-// For a max level of 14 (3 words having 4 levels, and 1 word with 2 levels)
-// there would be an average of 16k nodes skipped at the highest level.
-// 80,000 (store 4 words)
-// 70,000 (modify 14 words)
-// Potentially * 2 (very unlikely)
-// + read & execute costs
-// + 20,000 to store subscription
-// + 21,000 base transaction cost
-// + ? calldata
-// + ~100,000 Erc-20 transfer
-// ~= 450,000 Worst case (probability 1 per 200M)
-// This would cost ~$200
-//
-// 20,000 (store 4 words)
-// 10,000 (modify 2 words)
-// * 2
-// + read & execute costs
-// + 20,000 to store subscription
-// + 21,000 base transaction cost
-// + ? calldata
-// + ~100,000 Erc-20 transfer
-// ~= 201,000 Typical case
-//
-// Problem: Gas estimation being off can make it much more likely that only nodes with low skip exist.
-// In this case, the counter should not advance (requiring the high-skip node to be created)
-// One way to counter that may be to have the router update the skiplist, and consumers post
-// their subscriptions to a queue. That may use more gas overall, though.
+// For a max level of 14 there would be an average of 8k nodes skipped at
+// the highest level, giving O(log n) search/insert instead of the O(n)
+// `Vec::insert` shift this replaces.
 
-// This needs to be the efficient version, but leaving out the complex implementation
-// on account of simplicity. The real implementation would be gas efficient on insert
+type NodeIndex = u32;
+const NIL: NodeIndex = u32::MAX;
+const MAX_LEVEL: usize = 14;
 
-struct Word([u8; 32]);
+struct Node {
+    block: Block,
+    delta: i128,
+    // `forward[l]` is this node's next node at level `l`; `span_sum[l]` is
+    // the sum of `delta` over every node from (exclusive) this one up to
+    // and including `forward[l]`, at level-0 resolution. Together these
+    // let a downward walk accumulate a prefix sum without visiting every
+    // node in between.
+    forward: Vec<NodeIndex>,
+    span_sum: Vec<i128>,
+}
 
-impl Word {
-    fn as_u32s(self) -> [u32; 8] {
-        unsafe { transmute(self.0) }
+impl Node {
+    fn height(&self) -> usize {
+        self.forward.len()
     }
 }
 
-#[derive(Clone, Debug)]
 pub struct SkipList {
-    keys: Vec<Block>,
-    values: Vec<i128>,
+    arena: Vec<Option<Node>>,
+    // Freed slots, recycled by the next insert so repeated
+    // subscribe/unsubscribe churn doesn't grow the arena unboundedly.
+    freelist: Vec<NodeIndex>,
+    head_forward: [NodeIndex; MAX_LEVEL],
+    head_span_sum: [i128; MAX_LEVEL],
+    // A small xorshift generator for level selection. There's no
+    // randomness source available in this environment (an on-chain
+    // contract wouldn't have one either), so this is seeded deterministically
+    // and advanced on every insert.
+    rng: u64,
 }
 
-struct Levels0_2 {
-    level0: u32,
-    level1: u32,
+// Tracks, for each level, the last node visited before dropping down (or
+// NIL if that's still the head), which is where a new node at that level
+// needs to be spliced in.
+struct Search {
+    update: [NodeIndex; MAX_LEVEL],
+    // The running prefix sum up to (and including) `update[l]`, for every
+    // level. Since the search only ever moves forward, this is simply the
+    // value accumulated by the time level `l`'s descent finishes.
+    prefix_at_update: [i128; MAX_LEVEL],
+    found: Option<NodeIndex>,
 }
 
 impl SkipList {
     pub fn new() -> Self {
         Self {
-            keys: Vec::new(),
-            values: Vec::new(),
+            arena: Vec::new(),
+            freelist: Vec::new(),
+            head_forward: [NIL; MAX_LEVEL],
+            head_span_sum: [0; MAX_LEVEL],
+            rng: 0x2545_f491_4f6c_dd1d,
         }
     }
-    pub fn truncate_front(&mut self, index: usize) {
-        self.keys.drain(..index);
-        self.values.drain(..index);
+
+    fn node(&self, i: NodeIndex) -> &Node {
+        self.arena[i as usize].as_ref().unwrap()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Block, &i128)> {
-        self.keys.iter().zip(self.values.iter())
+    fn node_mut(&mut self, i: NodeIndex) -> &mut Node {
+        self.arena[i as usize].as_mut().unwrap()
+    }
+
+    fn forward(&self, node: NodeIndex, level: usize) -> NodeIndex {
+        if node == NIL {
+            self.head_forward[level]
+        } else {
+            self.node(node).forward[level]
+        }
+    }
+
+    fn span_sum(&self, node: NodeIndex, level: usize) -> i128 {
+        if node == NIL {
+            self.head_span_sum[level]
+        } else {
+            self.node(node).span_sum[level]
+        }
+    }
+
+    fn set_forward(&mut self, node: NodeIndex, level: usize, target: NodeIndex, span: i128) {
+        if node == NIL {
+            self.head_forward[level] = target;
+            self.head_span_sum[level] = span;
+        } else {
+            let n = self.node_mut(node);
+            n.forward[level] = target;
+            n.span_sum[level] = span;
+        }
     }
 
-    pub fn get_or_insert_mut(&mut self, k: Block) -> &mut i128 {
-        let i = match self.keys.binary_search(&k) {
-            Ok(i) => i,
-            Err(i) => {
-                self.values.insert(i, 0);
-                self.keys.insert(i, k);
-                i
+    // Walks from the head down to (but not past) `k`, recording the last
+    // node visited at each level before dropping down, along with the
+    // prefix sum up to that node.
+    fn search(&self, k: Block) -> Search {
+        let mut update = [NIL; MAX_LEVEL];
+        let mut prefix_at_update = [0i128; MAX_LEVEL];
+        let mut cur = NIL;
+        let mut acc = 0i128;
+
+        for level in (0..MAX_LEVEL).rev() {
+            loop {
+                let next = self.forward(cur, level);
+                if next == NIL || self.node(next).block >= k {
+                    break;
+                }
+                acc += self.span_sum(cur, level);
+                cur = next;
+            }
+            update[level] = cur;
+            prefix_at_update[level] = acc;
+        }
+
+        let found = {
+            let candidate = self.forward(cur, 0);
+            if candidate != NIL && self.node(candidate).block == k {
+                Some(candidate)
+            } else {
+                None
             }
         };
-        &mut self.values[i]
+
+        Search {
+            update,
+            prefix_at_update,
+            found,
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        let mut level = 1;
+        let mut bits = self.rng;
+        while level < MAX_LEVEL && bits & 1 == 1 {
+            level += 1;
+            bits >>= 1;
+        }
+        level
+    }
+
+    fn alloc(&mut self, node: Node) -> NodeIndex {
+        if let Some(i) = self.freelist.pop() {
+            self.arena[i as usize] = Some(node);
+            i
+        } else {
+            self.arena.push(Some(node));
+            (self.arena.len() - 1) as NodeIndex
+        }
+    }
+
+    pub fn get_or_insert_mut(&mut self, k: Block) -> DeltaMut<'_> {
+        let search = self.search(k);
+
+        let index = if let Some(found) = search.found {
+            found
+        } else {
+            let height = self.random_level();
+            let mut forward = Vec::with_capacity(height);
+            let mut span_sum = Vec::with_capacity(height);
+
+            // A brand new node starts at delta 0, so splicing it in never
+            // changes the sum of any span it ends up inside of; spans it
+            // becomes an endpoint of are simply split at zero cost.
+            for level in 0..height {
+                let target = self.forward(search.update[level], level);
+                let total_to_target = self.span_sum(search.update[level], level) + search.prefix_at_update[level];
+                let total_to_new = search.prefix_at_update[0];
+                let new_span = total_to_target - total_to_new;
+                forward.push(target);
+                span_sum.push(new_span);
+            }
+
+            let new_index = self.alloc(Node {
+                block: k,
+                delta: 0,
+                forward,
+                span_sum,
+            });
+
+            for level in 0..height {
+                let total_to_new = search.prefix_at_update[0];
+                let span_to_new = total_to_new - search.prefix_at_update[level];
+                self.set_forward(search.update[level], level, new_index, span_to_new);
+            }
+
+            new_index
+        };
+
+        let old_value = self.node(index).delta;
+        DeltaMut {
+            list: self,
+            block: k,
+            old_value,
+        }
+    }
+
+    // Removes the first (lowest-block) node, if any, adjusting the head's
+    // spans so later prefix-sum queries no longer count it.
+    fn remove_front(&mut self) {
+        let front = self.head_forward[0];
+        if front == NIL {
+            return;
+        }
+
+        let height = self.node(front).height();
+        let delta = self.node(front).delta;
+
+        for level in 0..MAX_LEVEL {
+            if level < height {
+                let n = self.node(front);
+                let target = n.forward[level];
+                let span = n.span_sum[level];
+                let new_span = self.head_span_sum[level] + span - delta;
+                self.head_forward[level] = target;
+                self.head_span_sum[level] = new_span;
+            } else {
+                self.head_span_sum[level] -= delta;
+            }
+        }
+
+        self.arena[front as usize] = None;
+        self.freelist.push(front);
+    }
+
+    pub fn truncate_front(&mut self, count: usize) {
+        for _ in 0..count {
+            self.remove_front();
+        }
+    }
+
+    // Note: `span_sum` gives a cached prefix sum of `delta` for search's own
+    // downward walk, but that's a sum of rate *changes*, not of the revenue
+    // those rates earned. Revenue is `current_fee * num_blocks` per segment
+    // between changes, so `collect()` still has to visit each entry up to
+    // `current_block` to know how long every rate was actually in effect —
+    // a cached total delta alone can't shortcut that walk.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            list: self,
+            next: self.head_forward[0],
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    next: NodeIndex,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Block, &'a i128);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == NIL {
+            return None;
+        }
+        let node = self.list.node(self.next);
+        self.next = node.forward.first().copied().unwrap_or(NIL);
+        Some((&node.block, &node.delta))
+    }
+}
+
+// A handle to a single entry's delta. Derefs like a plain `&mut i128` so
+// call sites are unchanged, but on drop it reconciles the cached span sums
+// above it with whatever the caller wrote, in O(log n).
+pub struct DeltaMut<'a> {
+    list: &'a mut SkipList,
+    block: Block,
+    old_value: i128,
+}
+
+impl<'a> Deref for DeltaMut<'a> {
+    type Target = i128;
+
+    fn deref(&self) -> &i128 {
+        let index = self.list.search(self.block).found.unwrap();
+        &self.list.node(index).delta
+    }
+}
+
+impl<'a> DerefMut for DeltaMut<'a> {
+    fn deref_mut(&mut self) -> &mut i128 {
+        let index = self.list.search(self.block).found.unwrap();
+        &mut self.list.node_mut(index).delta
+    }
+}
+
+impl<'a> Drop for DeltaMut<'a> {
+    fn drop(&mut self) {
+        let search = self.list.search(self.block);
+        let index = search.found.unwrap();
+        let new_value = self.list.node(index).delta;
+        let diff = new_value - self.old_value;
+        if diff == 0 {
+            return;
+        }
+
+        // Every span from the head down to this node, at every level,
+        // spans over (or lands exactly on) it - so each one picks up the
+        // same diff.
+        for level in 0..MAX_LEVEL {
+            let pred = search.update[level];
+            let span = self.list.span_sum(pred, level);
+            let target = self.list.forward(pred, level);
+            self.list.set_forward(pred, level, target, span + diff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_then_read_back() {
+        let mut list = SkipList::new();
+        *list.get_or_insert_mut(10) += 5;
+        *list.get_or_insert_mut(20) -= 3;
+
+        let entries: Vec<_> = list.iter().map(|(&b, &d)| (b, d)).collect();
+        assert_eq!(entries, vec![(10, 5), (20, -3)]);
+    }
+
+    #[test]
+    fn reinserting_the_same_block_accumulates() {
+        let mut list = SkipList::new();
+        *list.get_or_insert_mut(10) += 5;
+        *list.get_or_insert_mut(10) += 7;
+
+        let entries: Vec<_> = list.iter().map(|(&b, &d)| (b, d)).collect();
+        assert_eq!(entries, vec![(10, 12)]);
+    }
+
+    #[test]
+    fn iterates_in_ascending_block_order_regardless_of_insertion_order() {
+        let mut list = SkipList::new();
+        for &block in &[50, 10, 30, 20, 40] {
+            *list.get_or_insert_mut(block) += 1;
+        }
+
+        let blocks: Vec<_> = list.iter().map(|(&b, _)| b).collect();
+        assert_eq!(blocks, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn truncate_front_drops_only_the_given_prefix() {
+        let mut list = SkipList::new();
+        for block in 0..10 {
+            *list.get_or_insert_mut(block) += block + 1;
+        }
+
+        list.truncate_front(4);
+
+        let blocks: Vec<_> = list.iter().map(|(&b, _)| b).collect();
+        assert_eq!(blocks, vec![4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn truncate_front_recycles_slots() {
+        let mut list = SkipList::new();
+        for block in 0..100 {
+            *list.get_or_insert_mut(block) += 1;
+        }
+        list.truncate_front(100);
+        assert_eq!(list.freelist.len(), 100);
+
+        for block in 100..150 {
+            *list.get_or_insert_mut(block) += 1;
+        }
+        // The new inserts should have reused the freed slots rather than
+        // growing the arena further.
+        assert_eq!(list.arena.len(), 100);
+    }
+
+    #[test]
+    fn large_randomized_workload_matches_a_plain_map_reference() {
+        let mut list = SkipList::new();
+        let mut reference: std::collections::BTreeMap<Block, i128> = std::collections::BTreeMap::new();
+        let mut rng: u64 = 0x9E3779B97F4A7C15;
+
+        for i in 0..2000 {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let block = (rng % 500) as Block;
+            let delta = (i % 7) as i128 - 3;
+
+            *list.get_or_insert_mut(block) += delta;
+            *reference.entry(block).or_default() += delta;
+        }
+
+        let from_list: Vec<_> = list.iter().map(|(&b, &d)| (b, d)).collect();
+        let from_reference: Vec<_> = reference.into_iter().filter(|&(_, d)| d != 0 || true).collect();
+        assert_eq!(from_list, from_reference);
     }
 }